@@ -15,6 +15,8 @@
 // along with Substrate.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use codec::Decode;
 use rental;
 use tracing::info_span;
 
@@ -27,6 +29,14 @@ pub const WASM_NAME_KEY: &'static str = "proxied_wasm_name";
 
 const MAX_SPANS_LEN: usize = 1000;
 
+/// Number of generic `wasm_field_*` slots reserved on every span.
+///
+/// `tracing` requires all field names to be known when the span is constructed, so rather
+/// than building the field list dynamically from the runtime-supplied pairs, each span
+/// reserves this many slots up front: slot `i`'s key is recorded under `wasm_field_name_i`
+/// and its value under `wasm_field_i`. Pairs beyond this count are dropped with a warning.
+const MAX_WASM_FIELDS: usize = 8;
+
 thread_local! {
 	static PROXY: RefCell<TracingProxy> = RefCell::new(TracingProxy::new());
 }
@@ -37,11 +47,136 @@ pub fn create_registered_span(target: &str, name: &str) -> u64 {
 	PROXY.with(|proxy| proxy.borrow_mut().create_span(target, name))
 }
 
+/// Like `create_registered_span`, but additionally records the given SCALE-encoded
+/// `(key, value)` pairs on the span as structured fields.
+///
+/// `encoded_fields` must decode as a `Vec<(Vec<u8>, Vec<u8>)>` of UTF-8 key/value pairs. Keys
+/// and values beyond `MAX_WASM_FIELDS` pairs, or that fail to decode, are dropped with a
+/// warning; see `TracingProxy::create_span_with_fields` for how surviving pairs are mapped
+/// onto the span's fixed `wasm_field_*` slots.
+pub fn create_registered_span_with_fields(target: &str, name: &str, encoded_fields: &[u8]) -> u64 {
+	let fields = decode_fields(encoded_fields);
+	PROXY.with(|proxy| proxy.borrow_mut().create_span_with_fields(target, name, fields))
+}
+
 /// Exit a span by dropping it along with it's associated guard.
 pub fn exit_span(id: u64) {
 	PROXY.with(|proxy| proxy.borrow_mut().exit_span(id));
 }
 
+/// Record a `follows_from` relationship between two still-open proxied spans.
+///
+/// This is the same concept OpenTelemetry calls a span link: unlike `tracing`'s
+/// parent/child nesting, it lets a span declare that it is causally downstream of another
+/// one without being its child, which is needed for work triggered by, but not nested
+/// inside, an earlier operation (e.g. a deferred callback or a spawned task). If either
+/// `id` or `other_id` is not a currently open span, a warning is logged and the surviving
+/// span (if any) has `is_valid_trace = false` recorded on it.
+pub fn record_follows_from(id: u64, other_id: u64) {
+	PROXY.with(|proxy| proxy.borrow_mut().record_follows_from(id, other_id));
+}
+
+/// Number of spans evicted so far because `MAX_SPANS_LEN` was exceeded.
+///
+/// Exposed so the subscriber layer can surface it as a field on the root trace (the same
+/// way OpenTelemetry records dropped-attribute/link counts), giving operators visibility
+/// into trace truncation instead of only a log line.
+pub fn dropped_span_count() -> u64 {
+	PROXY.with(|proxy| proxy.borrow().dropped_count())
+}
+
+/// Emit a point-in-time `tracing` event, parented to whichever proxied span is currently
+/// entered (if any), so pallets get structured, span-scoped diagnostics without needing
+/// separate logging plumbing.
+///
+/// `level` is a numeric severity from the runtime, mapped as `0 = ERROR`, `1 = WARN`,
+/// `2 = INFO`, `3 = DEBUG`, anything else = `TRACE`. `encoded_fields` is decoded the same
+/// way as `create_registered_span_with_fields`; unlike a span's fields, an event's fields
+/// must all be known at the point it fires, so unused `wasm_field_*` slots are recorded as
+/// empty strings rather than left `Empty`.
+pub fn emit_event(target: &str, name: &str, level: u8, encoded_fields: &[u8]) {
+	let tracing_level = level_from_u8(level);
+	// Coarse, cheap pre-check against the globally configured max level, so a disabled
+	// DEBUG/TRACE event from a hot WASM path doesn't pay for decoding its fields just to be
+	// discarded; `tracing::event!` below still applies the real per-callsite/target filter.
+	if tracing_level > tracing::level_filters::LevelFilter::current() {
+		return;
+	}
+
+	if !PROXY.with(|proxy| proxy.borrow().has_open_span()) {
+		// Not an error: emitting outside any proxied span is a normal, unparented event.
+		log::debug!("emit_event: no proxied span currently open, event will be unparented");
+	}
+
+	let fields = decode_fields(encoded_fields);
+	warn_on_truncated_fields("event", fields.len());
+	let mut vals: [(&str, &str); MAX_WASM_FIELDS] = [("", ""); MAX_WASM_FIELDS];
+	for (i, (key, value)) in fields.iter().take(MAX_WASM_FIELDS).enumerate() {
+		vals[i] = (key.as_str(), value.as_str());
+	}
+
+	macro_rules! emit {
+		($level:expr) => {
+			tracing::event!(
+				$level,
+				proxied_wasm_target = target,
+				proxied_wasm_name = name,
+				wasm_field_name_0 = vals[0].0, wasm_field_0 = vals[0].1,
+				wasm_field_name_1 = vals[1].0, wasm_field_1 = vals[1].1,
+				wasm_field_name_2 = vals[2].0, wasm_field_2 = vals[2].1,
+				wasm_field_name_3 = vals[3].0, wasm_field_3 = vals[3].1,
+				wasm_field_name_4 = vals[4].0, wasm_field_4 = vals[4].1,
+				wasm_field_name_5 = vals[5].0, wasm_field_5 = vals[5].1,
+				wasm_field_name_6 = vals[6].0, wasm_field_6 = vals[6].1,
+				wasm_field_name_7 = vals[7].0, wasm_field_7 = vals[7].1,
+			)
+		};
+	}
+	match tracing_level {
+		tracing::Level::ERROR => emit!(tracing::Level::ERROR),
+		tracing::Level::WARN => emit!(tracing::Level::WARN),
+		tracing::Level::INFO => emit!(tracing::Level::INFO),
+		tracing::Level::DEBUG => emit!(tracing::Level::DEBUG),
+		tracing::Level::TRACE => emit!(tracing::Level::TRACE),
+	}
+}
+
+/// Maps a numeric WASM-side severity to a `tracing::Level`: `0 = ERROR`, `1 = WARN`,
+/// `2 = INFO`, `3 = DEBUG`, anything else = `TRACE`.
+fn level_from_u8(level: u8) -> tracing::Level {
+	match level {
+		0 => tracing::Level::ERROR,
+		1 => tracing::Level::WARN,
+		2 => tracing::Level::INFO,
+		3 => tracing::Level::DEBUG,
+		_ => tracing::Level::TRACE,
+	}
+}
+
+fn warn_on_truncated_fields(kind: &str, num_fields: usize) {
+	if num_fields > MAX_WASM_FIELDS {
+		log::warn!(
+			"Dropping {} structured {} field(s) beyond the MAX_WASM_FIELDS ({}) limit",
+			num_fields - MAX_WASM_FIELDS,
+			kind,
+			MAX_WASM_FIELDS,
+		);
+	}
+}
+
+fn decode_fields(encoded_fields: &[u8]) -> Vec<(String, String)> {
+	let raw: Vec<(Vec<u8>, Vec<u8>)> = match Decode::decode(&mut &encoded_fields[..]) {
+		Ok(raw) => raw,
+		Err(e) => {
+			log::warn!("Failed to decode WASM span fields, dropping them: {:?}", e);
+			return Vec::new();
+		}
+	};
+	raw.into_iter()
+		.map(|(k, v)| (String::from_utf8_lossy(&k).into_owned(), String::from_utf8_lossy(&v).into_owned()))
+		.collect()
+}
+
 rental! {
 	pub mod rent_span {
 		#[rental]
@@ -56,25 +191,50 @@ rental! {
 /// this is available when running with client (and relevant cli params).
 pub struct TracingProxy {
 	next_id: u64,
-	spans: Vec<(u64, rent_span::SpanAndGuard)>,
+	spans: HashMap<u64, rent_span::SpanAndGuard>,
+	/// Ids of currently-held guards, in the order they were entered: a LIFO stack where the
+	/// most recently entered span is the back element, and a FIFO queue for eviction, where
+	/// the oldest entered span is the front element. Every id in `spans` appears here exactly
+	/// once, and vice versa. A `VecDeque` keeps both `exit_span`'s LIFO unwind and
+	/// `create_span_with_fields`'s eviction O(1).
+	order: VecDeque<u64>,
+	/// Ids `exit_span` has been asked to close but whose guard is still held, because a
+	/// more-recently-entered span (above it in `order`) hasn't exited yet. See `exit_span`.
+	exited: HashSet<u64>,
+	/// Number of spans evicted because `MAX_SPANS_LEN` was exceeded.
+	dropped_count: u64,
 }
 
 impl Drop for TracingProxy {
 	fn drop(&mut self) {
-		while let Some((_, mut sg)) = self.spans.pop() {
-			sg.rent_all_mut(|s| { s.span.record("is_valid_trace", &false); });
+		// Drop guards in reverse creation order, which best respects tracing's own
+		// current-span stack for the common case of strictly nested spans.
+		for id in self.order.drain(..).rev() {
+			if let Some(mut sg) = self.spans.remove(&id) {
+				sg.rent_all_mut(|s| { s.span.record("is_valid_trace", &false); });
+			}
 		}
 	}
 }
 
 impl TracingProxy {
 	pub fn new() -> TracingProxy {
-		let spans: Vec<(u64, rent_span::SpanAndGuard)> = Vec::new();
 		TracingProxy {
 			next_id: 0,
-			spans,
+			spans: HashMap::new(),
+			order: VecDeque::new(),
+			exited: HashSet::new(),
+			dropped_count: 0,
 		}
 	}
+
+	fn dropped_count(&self) -> u64 {
+		self.dropped_count
+	}
+
+	fn has_open_span(&self) -> bool {
+		!self.spans.is_empty()
+	}
 }
 
 /// For spans to be recorded they must be registered in `span_dispatch`.
@@ -82,47 +242,276 @@ impl TracingProxy {
 	// The identifiers `wasm_target` and `wasm_name` must match their associated const,
 	// WASM_TARGET_KEY and WASM_NAME_KEY.
 	fn create_span(&mut self, proxied_wasm_target: &str, proxied_wasm_name: &str) -> u64 {
-		let span = info_span!(WASM_TRACE_IDENTIFIER, is_valid_trace = true, proxied_wasm_target, proxied_wasm_name);
+		self.create_span_with_fields(proxied_wasm_target, proxied_wasm_name, Vec::new())
+	}
+
+	fn create_span_with_fields(
+		&mut self,
+		proxied_wasm_target: &str,
+		proxied_wasm_name: &str,
+		fields: Vec<(String, String)>,
+	) -> u64 {
+		warn_on_truncated_fields("span", fields.len());
+		// `"otel.name"` is recognised by `tracing-opentelemetry` to rename the exported span
+		// (see the `otel` module). Declaring an `Empty` field costs nothing on its own, so it
+		// stays unconditional like the rest of the field list; only the `record` call below,
+		// which is the part that actually does OTel-specific work, is feature-gated.
+		let span = info_span!(
+			WASM_TRACE_IDENTIFIER,
+			is_valid_trace = true,
+			proxied_wasm_target,
+			proxied_wasm_name,
+			wasm_field_name_0 = tracing::field::Empty, wasm_field_0 = tracing::field::Empty,
+			wasm_field_name_1 = tracing::field::Empty, wasm_field_1 = tracing::field::Empty,
+			wasm_field_name_2 = tracing::field::Empty, wasm_field_2 = tracing::field::Empty,
+			wasm_field_name_3 = tracing::field::Empty, wasm_field_3 = tracing::field::Empty,
+			wasm_field_name_4 = tracing::field::Empty, wasm_field_4 = tracing::field::Empty,
+			wasm_field_name_5 = tracing::field::Empty, wasm_field_5 = tracing::field::Empty,
+			wasm_field_name_6 = tracing::field::Empty, wasm_field_6 = tracing::field::Empty,
+			wasm_field_name_7 = tracing::field::Empty, wasm_field_7 = tracing::field::Empty,
+			"otel.name" = tracing::field::Empty,
+		);
+		#[cfg(feature = "otlp-tracing")]
+		span.record("otel.name", &proxied_wasm_name);
+		for (i, (key, value)) in fields.into_iter().take(MAX_WASM_FIELDS).enumerate() {
+			span.record(format!("wasm_field_name_{}", i).as_str(), &key.as_str());
+			span.record(format!("wasm_field_{}", i).as_str(), &value.as_str());
+		}
 		self.next_id += 1;
 		let sg = rent_span::SpanAndGuard::new(
 			Box::new(span),
 			|span| span.enter(),
 		);
-		self.spans.push((self.next_id, sg));
-		let spans_len = self.spans.len();
-		if spans_len > MAX_SPANS_LEN {
-			// This is to prevent unbounded growth of Vec and could mean one of the following:
+		self.order.push_back(self.next_id);
+		self.spans.insert(self.next_id, sg);
+		if self.spans.len() > MAX_SPANS_LEN {
+			// This could mean one of the following:
 			// 1. Too many nested spans, or MAX_SPANS_LEN is too low.
 			// 2. Not correctly exiting spans due to drop impl not running (panic in runtime)
 			// 3. Not correctly exiting spans due to misconfiguration / misuse
-			log::warn!("MAX_SPANS_LEN exceeded, removing oldest span, recording `is_valid_trace = false`");
-			let mut sg = self.spans.remove(0).1;
-			sg.rent_all_mut(|s| { s.span.record("is_valid_trace", &false); });
+			//
+			// `order` is always in 1:1 sync with `spans` (see the field doc comment), so the
+			// oldest entered span is simply the front of the queue, evicted in O(1).
+			log::warn!("MAX_SPANS_LEN exceeded, evicting oldest span, recording `is_valid_trace = false`");
+			let oldest = self.order.pop_front().expect("just pushed an entry above, order is non-empty");
+			self.exited.remove(&oldest);
+			if let Some(mut sg) = self.spans.remove(&oldest) {
+				sg.rent_all_mut(|s| { s.span.record("is_valid_trace", &false); });
+				self.dropped_count += 1;
+			}
 		}
 		self.next_id
 	}
 
+	// `tracing`'s per-thread current-span stack is strict LIFO, so a guard can only be dropped
+	// once every guard entered after it has already been dropped. `exit_span` may be asked to
+	// close spans out of that order (overlapping/interleaved spans), so it defers: the id is
+	// recorded in `exited`, and we only actually pop and drop guards off the top of `order`
+	// for as long as the top is itself marked exited. A span entered after a deferred exit,
+	// and exited before the spans below it, unwinds the same way once its own turn comes.
 	fn exit_span(&mut self, id: u64) {
-		match self.spans.pop() {
-			Some(v) => {
-				let mut last_span_id = v.0;
-				while id < last_span_id {
-					log::warn!("Span ids not equal! id parameter given: {}, last span: {}", id, last_span_id);
-					if let Some(mut s) = self.spans.pop() {
-						last_span_id = s.0;
-						if id != last_span_id {
-							s.1.rent_all_mut(|s| { s.span.record("is_valid_trace", &false); });
-						}
-					} else {
-						log::warn!("Span id not found {}", id);
-						return;
-					}
-				}
+		if !self.spans.contains_key(&id) {
+			log::warn!("Span id: {} not found", id);
+			return;
+		}
+		self.exited.insert(id);
+		while let Some(&top) = self.order.back() {
+			if !self.exited.contains(&top) {
+				break;
 			}
-			None => {
-				log::warn!("Span id: {} not found", id);
-				return;
+			self.order.pop_back();
+			self.exited.remove(&top);
+			self.spans.remove(&top);
+		}
+	}
+
+	fn record_follows_from(&mut self, id: u64, other_id: u64) {
+		let id_exists = self.spans.contains_key(&id);
+		let other_exists = self.spans.contains_key(&other_id);
+		if !id_exists || !other_exists {
+			log::warn!(
+				"record_follows_from: span id {} or {} not found, marking the surviving span as invalid",
+				id, other_id,
+			);
+			let surviving = if id_exists { Some(id) } else if other_exists { Some(other_id) } else { None };
+			if let Some(surviving) = surviving {
+				if let Some(sg) = self.spans.get_mut(&surviving) {
+					sg.rent_all_mut(|s| { s.span.record("is_valid_trace", &false); });
+				}
 			}
+			return;
+		}
+		if id == other_id {
+			return;
+		}
+		let other_span_id = self.spans.get(&other_id).expect("checked above").rent_all(|s| s.span.id());
+		self.spans.get_mut(&id).expect("checked above")
+			.rent_all_mut(|s| { s.span.follows_from(other_span_id); });
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn nested_spans_exit_in_order() {
+		let mut proxy = TracingProxy::new();
+		let a = proxy.create_span("target", "a");
+		let b = proxy.create_span("target", "b");
+		proxy.exit_span(b);
+		proxy.exit_span(a);
+		assert!(proxy.spans.is_empty());
+		assert!(proxy.order.is_empty());
+	}
+
+	#[test]
+	fn interleaved_exits_defer_until_stack_unwinds() {
+		let mut proxy = TracingProxy::new();
+		let a = proxy.create_span("target", "a");
+		let b = proxy.create_span("target", "b");
+		let c = proxy.create_span("target", "c");
+
+		// Exiting the bottom of the stack first must not drop `b` or `c`'s guards yet, since
+		// `tracing`'s current-span stack would desync if they were dropped out of LIFO order.
+		proxy.exit_span(a);
+		assert!(proxy.spans.contains_key(&a));
+		assert!(proxy.spans.contains_key(&b));
+		assert!(proxy.spans.contains_key(&c));
+
+		proxy.exit_span(b);
+		assert!(proxy.spans.contains_key(&a));
+		assert!(proxy.spans.contains_key(&c));
+
+		// Exiting the top finally unwinds `c`, then the deferred `b` and `a` beneath it.
+		proxy.exit_span(c);
+		assert!(proxy.spans.is_empty());
+		assert!(proxy.order.is_empty());
+	}
+
+	#[test]
+	fn overlapping_span_created_after_deferred_exit_still_survives() {
+		let mut proxy = TracingProxy::new();
+		let a = proxy.create_span("target", "a");
+		let b = proxy.create_span("target", "b");
+		proxy.exit_span(a);
+		let c = proxy.create_span("target", "c");
+		proxy.exit_span(c);
+		assert!(proxy.spans.contains_key(&a));
+		assert!(!proxy.spans.contains_key(&c));
+		proxy.exit_span(b);
+		assert!(proxy.spans.is_empty());
+	}
+
+	#[test]
+	fn unknown_id_just_warns() {
+		let mut proxy = TracingProxy::new();
+		proxy.exit_span(42);
+		assert!(proxy.spans.is_empty());
+	}
+
+	#[test]
+	fn follows_from_between_two_open_spans_leaves_both_valid() {
+		let mut proxy = TracingProxy::new();
+		let a = proxy.create_span("target", "a");
+		let b = proxy.create_span("target", "b");
+		proxy.record_follows_from(a, b);
+		assert!(proxy.spans.contains_key(&a));
+		assert!(proxy.spans.contains_key(&b));
+	}
+
+	#[test]
+	fn follows_from_self_is_a_noop() {
+		let mut proxy = TracingProxy::new();
+		let a = proxy.create_span("target", "a");
+		proxy.record_follows_from(a, a);
+		assert!(proxy.spans.contains_key(&a));
+	}
+
+	#[test]
+	fn follows_from_both_missing_warns_without_panicking() {
+		let mut proxy = TracingProxy::new();
+		proxy.record_follows_from(1, 2);
+		assert!(proxy.spans.is_empty());
+	}
+
+	#[test]
+	fn follows_from_one_missing_marks_the_surviving_span_invalid() {
+		let mut proxy = TracingProxy::new();
+		let a = proxy.create_span("target", "a");
+		proxy.record_follows_from(a, 999);
+		// The surviving span stays open (only `is_valid_trace` is recorded on it); the
+		// missing id is never inserted.
+		assert!(proxy.spans.contains_key(&a));
+		assert!(!proxy.spans.contains_key(&999));
+	}
+
+	#[test]
+	fn exceeding_max_spans_len_evicts_the_oldest_span_first() {
+		let mut proxy = TracingProxy::new();
+		let first = proxy.create_span("target", "first");
+		for _ in 1..MAX_SPANS_LEN {
+			proxy.create_span("target", "filler");
 		}
+		assert_eq!(proxy.dropped_count(), 0);
+		assert!(proxy.spans.contains_key(&first));
+
+		// One more span pushes `spans.len()` past `MAX_SPANS_LEN`, evicting the oldest (FIFO).
+		proxy.create_span("target", "overflow");
+		assert_eq!(proxy.dropped_count(), 1);
+		assert!(!proxy.spans.contains_key(&first));
+		assert_eq!(proxy.spans.len(), MAX_SPANS_LEN);
+		assert_eq!(proxy.order.len(), MAX_SPANS_LEN);
+	}
+
+	#[test]
+	fn level_from_u8_maps_known_codes_and_falls_back_to_trace() {
+		assert_eq!(level_from_u8(0), tracing::Level::ERROR);
+		assert_eq!(level_from_u8(1), tracing::Level::WARN);
+		assert_eq!(level_from_u8(2), tracing::Level::INFO);
+		assert_eq!(level_from_u8(3), tracing::Level::DEBUG);
+		assert_eq!(level_from_u8(4), tracing::Level::TRACE);
+		assert_eq!(level_from_u8(255), tracing::Level::TRACE);
+	}
+}
+
+/// Bridges proxied WASM spans to an OpenTelemetry/OTLP exporter.
+///
+/// Enabled via the `otlp-tracing` feature. The `WASM_TRACE_IDENTIFIER` spans created by
+/// `TracingProxy` already carry everything `tracing-opentelemetry` needs to build a valid
+/// `opentelemetry::trace::SpanData`:
+/// - the `otel.name` field (recorded alongside `proxied_wasm_name` by every `create_span*`
+///   call) is `tracing-opentelemetry`'s well-known override for the exported span's name, so
+///   traces show up as the real WASM span name rather than as `WASM_TRACE_IDENTIFIER`;
+/// - every other recorded field (`proxied_wasm_target`, the `wasm_field_*` slots, ...)
+///   is mapped to an OTel attribute automatically by the layer;
+/// - `Span::follows_from` links, as recorded by `record_follows_from`, are translated into
+///   OTel span links by the same layer, with no extra work needed here.
+#[cfg(feature = "otlp-tracing")]
+pub mod otel {
+	use opentelemetry::KeyValue;
+	use opentelemetry::trace::TraceError;
+	use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+	/// Build the subscriber that should be installed as the global default so that spans
+	/// recorded through [`super::TracingProxy`] (and the rest of the node) are exported to
+	/// an OTLP collector. This composes the `tracing-opentelemetry` layer on top of a bare
+	/// `Registry`; callers that also want console/log output should further `.with(...)` a
+	/// formatting layer onto the result, per the `tracing::Subscriber` requirement noted on
+	/// [`super::TracingProxy`].
+	pub fn otlp_subscriber(
+		service_name: &str,
+	) -> Result<impl tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>, TraceError> {
+		let tracer = opentelemetry_otlp::new_pipeline()
+			.tracing()
+			.with_exporter(opentelemetry_otlp::new_exporter().tonic())
+			.with_trace_config(
+				opentelemetry_sdk::trace::config().with_resource(opentelemetry_sdk::Resource::new(vec![
+					KeyValue::new("service.name", service_name.to_string()),
+				])),
+			)
+			.install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+		Ok(Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer)))
 	}
 }